@@ -10,6 +10,10 @@ use vst2::api::{Events,Supported};
 use std::f64::consts::PI;
 
 use std::os::raw::c_void;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::collections::HashMap;
 
 /// Convert the midi note into the equivalent frequency.
 ///
@@ -20,23 +24,63 @@ fn midi_note_to_hz(note: u8) -> f64 {
     (A4 / 32.0) * ((note as f64 - 9.0) / 12.0).exp2()
 }
 
+/// Encode `value` as a MIDI variable-length quantity and append it to `buf`:
+/// 7-bit groups, most-significant group first, with the high bit set on
+/// every byte except the last.
+fn write_vlq(buf: &mut Vec<u8>, value: u32) {
+    let mut buffer = value & 0x7F;
+    let mut remaining = value >> 7;
+    while remaining > 0 {
+        buffer <<= 8;
+        buffer |= 0x80 | (remaining & 0x7F);
+        remaining >>= 7;
+    }
+
+    loop {
+        buf.push((buffer & 0xFF) as u8);
+        if buffer & 0x80 != 0 {
+            buffer >>= 8;
+        } else {
+            break;
+        }
+    }
+}
+
 struct NotePress {
     note: u8,
+    velocity: f32,  // how hard the note was struck, in the range 0.0 to 1.0
     pressed_time: f64,  // time at which the note was pressed
     released_time: f64,  // time at which the note was released
+    released_level: f64,  // envelope value at the moment of release, the level the release phase decays from
     is_pressed: bool,  // true, if note is currently pressed
+    sustained: bool,  // true, if the key was released but is being held by the sustain pedal
+}
+
+struct MidiRecording {
+    events: Vec<u8>,  // delta-time (VLQ) + status + data bytes for each recorded event
+    last_event_time: u64,  // time of the last recorded event, in samples
 }
 
 struct SineSynth {
     // Parameters
     attack: f64,
+    decay: f64,
+    sustain: f64,
     release: f64,
+    brightness: f64,  // how many of the HARMONICS partials are mixed in, 0.0 to 1.0
+    pitch_bend: f64,  // current pitch-bend offset, in cents
+    pedal_down: bool,  // true, while the sustain pedal (CC 64) is held
+    cc_map: HashMap<u8, i32>,  // CC number -> parameter index, for CCs other than the sustain pedal
+    pending_cc_learn: Option<u8>,  // CC number awaiting a target parameter from the "CC Learn Target" parameter
 
     sample_rate: f64,
     time: f64,
 
     notes: Vec<NotePress>,  // all currently pressed/just pressed notes
 
+    wav_recording: Option<Vec<i16>>,  // Some(samples) while bouncing output to a WAV file
+    midi_recording: Option<MidiRecording>,  // Some(..) while logging incoming MIDI events
+
     editor: SineSynthEditor,
 }
 
@@ -45,6 +89,58 @@ impl SineSynth {
         1.0 / self.sample_rate
     }
 
+    /// How many entries of `HARMONICS` are currently active, driven by `brightness`.
+    ///
+    /// At `brightness` 0.0 only the fundamental sounds; at 1.0 all partials do.
+    fn num_active_partials(&self) -> usize {
+        1 + (self.brightness * (HARMONICS.len() - 1) as f64).round() as usize
+    }
+
+    /// Sum the active partials into a single sample, normalized so the total
+    /// amplitude stays <= 1.
+    fn additive_signal(&self, freq: f64, t: f64) -> f64 {
+        let active = &HARMONICS[..self.num_active_partials()];
+        let total_amp: f64 = active.iter().map(|&(_, amp)| amp).sum();
+
+        active.iter()
+            .map(|&(harmonic, amp)| amp * (t * freq * harmonic as f64 * TAU).sin())
+            .sum::<f64>() / total_amp
+    }
+
+    /// Compute the ADSR envelope multiplier for a note at time `t`.
+    ///
+    /// While the note is held this ramps 0 -> 1 over `attack`, then
+    /// 1 -> `sustain` over `decay`, then holds at `sustain`. Once released
+    /// it falls away from the level it had at the moment of release
+    /// (`released_level`) with an exponential decay of time constant
+    /// `release`, which avoids the click a hard linear cutoff produces.
+    fn envelope(&self, note_press: &NotePress, t: f64) -> f64 {
+        SineSynth::envelope_with(self.attack, self.decay, self.sustain, self.release, note_press, t)
+    }
+
+    /// Same as `envelope`, but takes the ADSR parameters by value so it can
+    /// be used in places (like `Vec::retain`) where `self` is already
+    /// partially borrowed.
+    fn envelope_with(attack: f64, decay: f64, sustain: f64, release: f64, note_press: &NotePress, t: f64) -> f64 {
+        if note_press.is_pressed {
+            let time_since_press = t - note_press.pressed_time;
+            if time_since_press < attack {
+                time_since_press / attack
+            } else if time_since_press < attack + decay {
+                let decay_progress = (time_since_press - attack) / decay;
+                1.0 + (sustain - 1.0) * decay_progress
+            } else {
+                sustain
+            }
+        } else {
+            // Decay from the envelope's actual value at the moment of release
+            // (not always `sustain`), so releasing mid-attack/decay doesn't
+            // jump the amplitude and click.
+            let time_since_release = t - note_press.released_time;
+            note_press.released_level * (-(time_since_release / release)).exp()
+        }
+    }
+
     /// Process an incoming midi event.
     ///
     /// The midi data is split up like so:
@@ -57,22 +153,100 @@ impl SineSynth {
     /// [source]: http://www.midimountain.com/midi/midi_status.htm
     fn process_midi_event(&mut self, data: [u8; 3]) {
         match data[0] {
-            128 => self.note_off(data[1]),
-            144 => self.note_on(data[1]),
+            128 => { self.record_midi_event(data); self.note_off(data[1]); },
+            144 => { self.record_midi_event(data); self.note_on(data[1], data[2]); },
+            176 => { self.record_midi_event(data); self.control_change(data[1], data[2]); },
+            224 => self.pitch_bend(data[1], data[2]),
             _ => ()
         }
     }
 
-    fn note_on(&mut self, note: u8) {
+    /// Log a NoteOn/NoteOff/CC event into `midi_recording`, if a MIDI capture
+    /// is in progress, as a VLQ delta time (in milliseconds) followed by the
+    /// raw status and data bytes.
+    fn record_midi_event(&mut self, data: [u8; 3]) {
+        let current_sample = (self.time * self.sample_rate).round() as u64;
+
+        if let Some(ref mut recording) = self.midi_recording {
+            let delta_samples = current_sample.saturating_sub(recording.last_event_time);
+            let delta_ms = (delta_samples as f64 * 1000.0 / self.sample_rate).round() as u32;
+
+            write_vlq(&mut recording.events, delta_ms);
+            recording.events.push(data[0]);
+            recording.events.push(data[1]);
+            recording.events.push(data[2]);
+            recording.last_event_time = current_sample;
+        }
+    }
+
+    fn control_change(&mut self, controller: u8, value: u8) {
+        match controller {
+            64 => self.set_sustain_pedal(value >= 64),
+            _ => {
+                if let Some(&parameter_index) = self.cc_map.get(&controller) {
+                    self.set_parameter(parameter_index, value as f32 / 127.0);
+                }
+            },
+        }
+    }
+
+    /// Map a CC number onto a parameter index, so that future Control Change
+    /// messages for that CC call `set_parameter` on it. Overwrites any
+    /// existing mapping for the same CC.
+    fn map_cc_to_parameter(&mut self, controller: u8, parameter_index: i32) {
+        self.cc_map.insert(controller, parameter_index);
+    }
+
+    /// Remove a CC-to-parameter mapping, if one exists.
+    fn unmap_cc(&mut self, controller: u8) {
+        self.cc_map.remove(&controller);
+    }
+
+    /// Set the sustain pedal state. When the pedal lifts, every note that was
+    /// released while it was down (`sustained`) begins its release phase.
+    fn set_sustain_pedal(&mut self, down: bool) {
+        if self.pedal_down && !down {
+            let time = self.time;
+            let (attack, decay, sustain, release) = (self.attack, self.decay, self.sustain, self.release);
+
+            for note_press in self.notes.iter_mut() {
+                if note_press.sustained {
+                    let released_level = SineSynth::envelope_with(attack, decay, sustain, release, note_press, time);
+                    note_press.sustained = false;
+                    note_press.is_pressed = false;
+                    note_press.released_time = time;
+                    note_press.released_level = released_level;
+                }
+            }
+        }
+
+        self.pedal_down = down;
+    }
+
+    /// Handle a pitch-bend message, combining the LSB/MSB into the 14-bit
+    /// bend value and mapping it around the centre (8192) into a signed
+    /// offset in cents, scaled so the full 14-bit range spans
+    /// +/- `PITCH_BEND_RANGE_CENTS`. The result is not clamped.
+    fn pitch_bend(&mut self, lsb: u8, msb: u8) {
+        let value = ((msb as u16) << 7) | (lsb as u16);
+        let normalized = (value as f64 - 8192.0) / 8192.0;
+
+        self.pitch_bend = normalized * PITCH_BEND_RANGE_CENTS;
+    }
+
+    fn note_on(&mut self, note: u8, velocity: u8) {
         // sanity check, make sure note isn't already in list?
         self.notes.retain(|ref x| x.note != note);
 
         // make a new note
         let new_note_press = NotePress {
             note: note,
+            velocity: velocity as f32 / 127.0,
             pressed_time: self.time, // current time
             released_time: 0.0, // null
+            released_level: 0.0, // null
             is_pressed: true,
+            sustained: false,
         };
 
         self.notes.push(new_note_press);
@@ -80,29 +254,158 @@ impl SineSynth {
 
     fn note_off(&mut self, note: u8) {
 
+        let pedal_down = self.pedal_down;
+        let time = self.time;
         match self.notes.iter().position(|ref x| x.note == note) {
             Some(i) => {
+                let released_level = self.envelope(&self.notes[i], time);
                 let note_press = self.notes.get_mut(i).unwrap();
-                note_press.is_pressed = false;
-                note_press.released_time = self.time;
+                if pedal_down {
+                    // Keep sounding at the held level until the pedal lifts.
+                    note_press.sustained = true;
+                } else {
+                    note_press.is_pressed = false;
+                    note_press.released_time = time;
+                    note_press.released_level = released_level;
+                }
             },
             None => (),
         };
     }
+
+    /// Start (or restart) accumulating output samples for a WAV bounce.
+    fn start_recording(&mut self) {
+        self.wav_recording = Some(Vec::new());
+    }
+
+    /// Stop accumulating samples and write them out as a standard RIFF/WAVE
+    /// file: a 44-byte header (`fmt ` chunk, PCM format 1, the current
+    /// `sample_rate` and channel count) followed by the interleaved 16-bit
+    /// sample data.
+    fn stop_recording(&mut self, path: &str) -> io::Result<()> {
+        let samples = match self.wav_recording.take() {
+            Some(samples) => samples,
+            None => return Ok(()),
+        };
+
+        let channels = self.get_info().outputs as u16;
+        let bits_per_sample: u16 = 16;
+        let byte_rate = self.sample_rate as u32 * channels as u32 * (bits_per_sample as u32 / 8);
+        let block_align = channels * (bits_per_sample / 8);
+        let data_size = (samples.len() * 2) as u32;
+
+        let mut file = File::create(path)?;
+
+        file.write_all(b"RIFF")?;
+        file.write_all(&(36 + data_size).to_le_bytes())?;
+        file.write_all(b"WAVE")?;
+
+        file.write_all(b"fmt ")?;
+        file.write_all(&16u32.to_le_bytes())?;
+        file.write_all(&1u16.to_le_bytes())?; // PCM
+        file.write_all(&channels.to_le_bytes())?;
+        file.write_all(&(self.sample_rate as u32).to_le_bytes())?;
+        file.write_all(&byte_rate.to_le_bytes())?;
+        file.write_all(&block_align.to_le_bytes())?;
+        file.write_all(&bits_per_sample.to_le_bytes())?;
+
+        file.write_all(b"data")?;
+        file.write_all(&data_size.to_le_bytes())?;
+        for sample in samples.iter() {
+            file.write_all(&sample.to_le_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    /// Start (or restart) logging incoming MIDI events for a Standard MIDI
+    /// File capture.
+    fn start_midi_recording(&mut self) {
+        let current_sample = (self.time * self.sample_rate).round() as u64;
+        self.midi_recording = Some(MidiRecording { events: Vec::new(), last_event_time: current_sample });
+    }
+
+    /// Stop logging events and write them out as a Standard MIDI File: an
+    /// `MThd` header (format 0, 1 track, a division of 1ms per tick) followed
+    /// by a single `MTrk` chunk ending with the `FF 2F 00` end-of-track meta
+    /// event.
+    fn stop_midi_recording(&mut self, path: &str) -> io::Result<()> {
+        let recording = match self.midi_recording.take() {
+            Some(recording) => recording,
+            None => return Ok(()),
+        };
+
+        let mut track_data = recording.events;
+        track_data.extend_from_slice(&[0x00, 0xFF, 0x2F, 0x00]); // end of track
+
+        let mut file = File::create(path)?;
+
+        file.write_all(b"MThd")?;
+        file.write_all(&6u32.to_be_bytes())?;
+        file.write_all(&0u16.to_be_bytes())?; // format 0: a single track
+        file.write_all(&1u16.to_be_bytes())?; // ntrks
+        file.write_all(&[0xE7, 40])?; // SMPTE division: -25 fps * 40 ticks/frame = 1ms/tick
+
+        file.write_all(b"MTrk")?;
+        file.write_all(&(track_data.len() as u32).to_be_bytes())?;
+        file.write_all(&track_data)?;
+
+        Ok(())
+    }
 }
 
 pub const TAU : f64 = PI * 2.0;
 
+/// Harmonic series used to build up the tone: (harmonic number, relative amplitude).
+///
+/// The fundamental dominates, with a handful of partials added on top to give
+/// the oscillator some colour instead of a pure, test-tone sine.
+const HARMONICS: [(u32, f64); 5] = [(1, 1.0), (2, 0.3), (3, 0.15), (4, 0.08), (7, 0.02)];
+
+/// Maximum pitch-bend deviation, in cents, at either end of the pitch-bend wheel.
+const PITCH_BEND_RANGE_CENTS: f64 = 200.0;
+
+/// Where a WAV bounce is written when the "Record WAV" parameter is toggled off.
+const WAV_OUTPUT_PATH: &'static str = "output.wav";
+
+/// Where a MIDI capture is written when the "Record MIDI" parameter is toggled off.
+const MIDI_OUTPUT_PATH: &'static str = "output.mid";
+
+/// Highest parameter index the "CC Learn Target" parameter can map a CC onto
+/// (the synth/recording parameters; the CC-learn parameters themselves are
+/// excluded, mapping a CC onto them wouldn't make sense).
+const MAX_CC_MAPPABLE_PARAMETER: i32 = 6;
+
+/// Default CC-to-parameter mapping: CC 73 (attack time) and CC 72 (release
+/// time) are the de facto standard knobs on MIDI controllers, so they're
+/// wired up to the matching parameters out of the box.
+fn default_cc_map() -> HashMap<u8, i32> {
+    let mut map = HashMap::new();
+    map.insert(73, 0); // Attack
+    map.insert(72, 3); // Release
+    map
+}
+
 impl Default for SineSynth {
     fn default() -> SineSynth {
         SineSynth {
             attack: 0.0001,
+            decay: 0.1,
+            sustain: 0.8,
             release: 0.0001,
+            brightness: 1.0,
+            pitch_bend: 0.0,
+            pedal_down: false,
+            cc_map: default_cc_map(),
+            pending_cc_learn: None,
 
             sample_rate: 44100.0,
             time: 0.0,
             notes: Vec::new(),
 
+            wav_recording: None,
+            midi_recording: None,
+
             editor: Default::default()
         }
     }
@@ -117,7 +420,7 @@ impl Plugin for SineSynth {
             category: Category::Synth,
             inputs: 2,
             outputs: 2,
-            parameters: 2,
+            parameters: 9,
             initial_delay: 0,
             ..Info::default()
         }
@@ -126,15 +429,60 @@ impl Plugin for SineSynth {
     fn get_parameter(&self, index: i32) -> f32 {
         match index {
             0 => self.attack as f32,
-            1 => self.release as f32,
+            1 => self.decay as f32,
+            2 => self.sustain as f32,
+            3 => self.release as f32,
+            4 => self.brightness as f32,
+            5 => if self.wav_recording.is_some() { 1.0 } else { 0.0 },
+            6 => if self.midi_recording.is_some() { 1.0 } else { 0.0 },
+            7 => self.pending_cc_learn.map(|cc| cc as f32 / 127.0).unwrap_or(0.0),
+            8 => 0.0, // write-only trigger, see set_parameter
             _ => 0.0,
         }
     }
 
     fn set_parameter(&mut self, index: i32, value: f32) {
         match index {
-            0 => self.attack = value.max(1.0) as f64,
-            1 => self.release = value.max(1.0) as f64,
+            // attack/decay/release are divided into in the envelope, so floor them
+            // just above zero to avoid a division by zero turning into a NaN/Inf click.
+            0 => self.attack = value.max(1e-4).min(1.0) as f64,
+            1 => self.decay = value.max(1e-4).min(1.0) as f64,
+            2 => self.sustain = value.min(1.0) as f64,
+            3 => self.release = value.max(1e-4).min(1.0) as f64,
+            4 => self.brightness = value.min(1.0) as f64,
+            5 => {
+                if value >= 0.5 {
+                    if self.wav_recording.is_none() {
+                        self.start_recording();
+                    }
+                } else {
+                    let _ = self.stop_recording(WAV_OUTPUT_PATH);
+                }
+            },
+            6 => {
+                if value >= 0.5 {
+                    if self.midi_recording.is_none() {
+                        self.start_midi_recording();
+                    }
+                } else {
+                    let _ = self.stop_midi_recording(MIDI_OUTPUT_PATH);
+                }
+            },
+            // "CC Learn Source": picks the CC number a subsequent "CC Learn
+            // Target" write will be mapped from.
+            7 => self.pending_cc_learn = Some((value * 127.0).round() as u8),
+            // "CC Learn Target": maps the pending CC onto a parameter index
+            // (0 clears the mapping instead), then forgets the pending CC.
+            8 => {
+                if let Some(cc) = self.pending_cc_learn.take() {
+                    if value <= 0.0 {
+                        self.unmap_cc(cc);
+                    } else {
+                        let parameter_index = (value * MAX_CC_MAPPABLE_PARAMETER as f32).round() as i32;
+                        self.map_cc_to_parameter(cc, parameter_index);
+                    }
+                }
+            },
             _ => (),
         }
     }
@@ -142,7 +490,14 @@ impl Plugin for SineSynth {
     fn get_parameter_name(&self, index: i32) -> String {
         match index {
             0 => "Attack".to_string(),
-            1 => "Release".to_string(),
+            1 => "Decay".to_string(),
+            2 => "Sustain".to_string(),
+            3 => "Release".to_string(),
+            4 => "Brightness".to_string(),
+            5 => "Record WAV".to_string(),
+            6 => "Record MIDI".to_string(),
+            7 => "CC Learn Source".to_string(),
+            8 => "CC Learn Target".to_string(),
             _ => "".to_string(),
         }
     }
@@ -150,7 +505,14 @@ impl Plugin for SineSynth {
     fn get_parameter_text(&self, index: i32) -> String {
         match index {
             0 => format!("{}", self.attack),
-            1 => format!("{}", self.release),
+            1 => format!("{}", self.decay),
+            2 => format!("{}", self.sustain),
+            3 => format!("{}", self.release),
+            4 => format!("{}", self.brightness),
+            5 => if self.wav_recording.is_some() { "Recording".to_string() } else { "Off".to_string() },
+            6 => if self.midi_recording.is_some() { "Recording".to_string() } else { "Off".to_string() },
+            7 => self.pending_cc_learn.map(|cc| format!("{}", cc)).unwrap_or("none".to_string()),
+            8 => "".to_string(),
             _ => "".to_string(),
         }
     }
@@ -159,6 +521,13 @@ impl Plugin for SineSynth {
         match index {
             0 => "s".to_string(),
             1 => "s".to_string(),
+            2 => "".to_string(),
+            3 => "s".to_string(),
+            4 => "".to_string(),
+            5 => "".to_string(),
+            6 => "".to_string(),
+            7 => "".to_string(),
+            8 => "".to_string(),
             _ => "".to_string(),
         }
     }
@@ -184,47 +553,59 @@ impl Plugin for SineSynth {
 
         let per_sample = self.time_per_sample();
 
+        // `buffer.zip()` is channel-major (all samples of one channel, then
+        // the next), so samples destined for the WAV recording are buffered
+        // per channel here and interleaved frame-by-frame below, rather than
+        // pushed straight into `wav_recording` in channel-major order.
+        let mut channel_pcm: Vec<Vec<i16>> = Vec::new();
+
         for (input_buffer, output_buffer) in buffer.zip() {
             let mut t = self.time;
+            let mut pcm_samples = if self.wav_recording.is_some() { Some(Vec::with_capacity(samples)) } else { None };
 
             for (_, output_sample) in input_buffer.iter().zip(output_buffer) {
                 let num_notes = self.notes.len();
 
                 for note_press in self.notes.iter() {
-                    let signal = (t * midi_note_to_hz(note_press.note) * TAU).sin();
+                    let freq = midi_note_to_hz(note_press.note) * (self.pitch_bend / 1200.0).exp2();
+                    let signal = self.additive_signal(freq, t);
 
-                    let attack = 0.01;
-                    let release = 0.01;
-
-                    // Apply attack
-                    let time_since_press = t - note_press.pressed_time;
-                    let alpha = if time_since_press < attack {
-                        time_since_press / self.attack
-                    } else {
-                        1.0
-                    };
+                    let envelope = self.envelope(note_press, t);
 
-                    // Apply release
-                    let beta = if note_press.is_pressed {
-                        1.0
-                    } else {
-                        let time_since_release = t - note_press.released_time;
-                        if time_since_release < release {
-                            1.0 - (time_since_release / release)
-                        } else {
-                            0.0
-                        }
-                    };
-
-                    let multiplier = alpha * beta / (num_notes as f64);
+                    let multiplier = envelope * (note_press.velocity as f64) / (num_notes as f64);
                     *output_sample += (signal * multiplier) as f32;
                 }
 
+                if let Some(ref mut pcm_samples) = pcm_samples {
+                    let pcm_sample = (*output_sample as f64 * i16::max_value() as f64)
+                        .max(i16::min_value() as f64)
+                        .min(i16::max_value() as f64) as i16;
+                    pcm_samples.push(pcm_sample);
+                }
+
                 t += per_sample;
             }
+
+            if let Some(pcm_samples) = pcm_samples {
+                channel_pcm.push(pcm_samples);
+            }
+        }
+
+        if let Some(ref mut recording) = self.wav_recording {
+            for frame in 0..samples {
+                for channel in channel_pcm.iter() {
+                    recording.push(channel[frame]);
+                }
+            }
         }
 
         self.time += samples as f64 * per_sample;
+
+        // Drop voices that have decayed into silence.
+        let (attack, decay, sustain, release, time) = (self.attack, self.decay, self.sustain, self.release, self.time);
+        self.notes.retain(|note_press| {
+            note_press.is_pressed || SineSynth::envelope_with(attack, decay, sustain, release, note_press, time) >= 1e-4
+        });
     }
 
     fn can_do(&self, can_do: CanDo) -> Supported {